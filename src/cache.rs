@@ -0,0 +1,80 @@
+extern crate serde;
+extern crate serde_json;
+
+use self::serde::{Serialize, Deserialize};
+use crate::formats::err_from_str;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Size and modified-time fingerprint of one source clip, cheap enough to
+/// gather with a single `stat` per file rather than opening or hashing it.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct InputFingerprint {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified_secs: i64,
+}
+
+/// Per-event cache entry: the fingerprints of the source clips that produced
+/// its mosaic/camera outputs, used to detect when a re-run can be skipped.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CacheEntry {
+    pub inputs: Vec<InputFingerprint>,
+}
+
+/// Maps an event folder (as a string path) to the fingerprints of the inputs
+/// that last produced its outputs. Persisted to disk so re-runs over a
+/// growing SentryClips library skip events that haven't changed.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ProcessingCache {
+    entries: HashMap<String, CacheEntry>,
+}
+
+impl ProcessingCache {
+    pub fn new() -> ProcessingCache {
+        ProcessingCache { entries: HashMap::new() }
+    }
+
+    pub fn get(&self, folder: &Path) -> Option<&CacheEntry> {
+        self.entries.get(&folder.display().to_string())
+    }
+
+    pub fn set(&mut self, folder: &Path, entry: CacheEntry) {
+        self.entries.insert(folder.display().to_string(), entry);
+    }
+}
+
+/// Loads a persisted cache from `path`, like czkawka's
+/// `load_cache_from_file_generalized_by_path`: a missing file just means an
+/// empty/cold cache rather than an error.
+pub fn load_cache_from_file(path: &Path) -> io::Result<ProcessingCache> {
+    if !path.exists() {
+        return Ok(ProcessingCache::new());
+    }
+    let file = File::open(path)?;
+    serde_json::from_reader(file).or_else(|err| {
+        log::warn!("Cannot parse cache file {}, starting fresh: {}", path.display(), err);
+        Ok(ProcessingCache::new())
+    })
+}
+
+pub fn save_cache_to_file(cache: &ProcessingCache, path: &Path) -> io::Result<()> {
+    let file = File::create(path)?;
+    serde_json::to_writer(file, cache)
+        .map_err(|e| err_from_str(format!("Cannot write cache file {}: {}", path.display(), e).as_str()))
+}
+
+/// Stats (not opens) each input clip to build its fingerprint. Lazy by
+/// design: callers only pay for metadata gathering, never a full read.
+pub fn fingerprint_inputs(paths: &[PathBuf]) -> io::Result<Vec<InputFingerprint>> {
+    paths.iter().map(|path| {
+        let meta = std::fs::metadata(path)?;
+        let modified_secs = meta.modified()?.duration_since(UNIX_EPOCH)
+            .map_err(|e| err_from_str(format!("Cannot read modified time for {}: {}", path.display(), e).as_str()))?
+            .as_secs() as i64;
+        Ok(InputFingerprint { path: path.clone(), size: meta.len(), modified_secs })
+    }).collect()
+}
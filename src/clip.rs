@@ -1,8 +1,14 @@
 extern crate chrono;
+extern crate rayon;
 
 use std::path::{PathBuf, Path};
 use self::chrono::{NaiveDateTime, Utc};
+use self::rayon::prelude::*;
 use crate::camera::{CameraFile, Camera};
+use crate::cache::{CacheEntry, ProcessingCache, fingerprint_inputs};
+use crate::ffmpeg_pool::ProcessingPool;
+use crate::metadata::{ClipMetadata, probe};
+use crate::mp4mux;
 use std::fs::{DirEntry, File, remove_file};
 use std::io;
 
@@ -16,6 +22,32 @@ pub struct SentryClip {
     pub clips: Vec<CameraFile>,
 }
 
+/// Builds a `SentryClip` per event folder, processing independent events
+/// concurrently. Parallelism (and therefore the number of ffmpeg children
+/// `concatenate_camera_files`/`create_mosaic` may spawn at once across the
+/// whole tree) is bounded by `pool`. Events whose mosaic already exists and
+/// whose source clips are unchanged per `cache` are skipped unless `force`
+/// is set.
+pub fn process_tree(entries: Vec<DirEntry>, pool: &ProcessingPool, cache: &ProcessingCache, force: bool) -> Vec<SentryClip> {
+    pool.install(|| {
+        entries.par_iter().filter_map(|entry| {
+            match SentryClip::from_folder(entry) {
+                Ok(clip) => {
+                    match clip.is_up_to_date(cache) {
+                        Ok(true) if !force => {
+                            log::info!("Skipping already-processed event {}", clip.folder.display());
+                            None
+                        }
+                        Ok(_) => Some(clip),
+                        Err(err) => { log::error!("Cannot check cache for event {}: {}", clip.folder.display(), err); Some(clip) }
+                    }
+                },
+                Err(err) => { log::error!("Found error processing event {}: {}", entry.path().display(), err); None },
+            }
+        }).collect()
+    })
+}
+
 impl SentryClip {
     pub fn from_folder(entry: &DirEntry) -> io::Result<SentryClip> {
         let clips = process_folder(entry)?;
@@ -24,6 +56,27 @@ impl SentryClip {
         Ok(SentryClip { folder, when, clips })
     }
 
+    fn distinct_cameras(&self) -> Vec<&Camera> {
+        let mut cameras: Vec<&Camera> = Vec::new();
+        for clip in &self.clips {
+            if !cameras.iter().any(|c| c.eq(&&clip.camera)) {
+                cameras.push(&clip.camera);
+            }
+        }
+        cameras
+    }
+
+    /// Concatenates every camera's files for this event, one ffmpeg
+    /// invocation per camera, running up to `pool`'s parallelism limit.
+    pub fn concatenate_all_cameras(&self, pool: &ProcessingPool) -> io::Result<Vec<(String, Camera)>> {
+        let cameras = self.distinct_cameras();
+        pool.install(|| {
+            cameras.par_iter()
+                .map(|camera| self.concatenate_camera_files(camera).map(|path| (path, (*camera).clone())))
+                .collect::<io::Result<Vec<(String, Camera)>>>()
+        })
+    }
+
     pub fn is_empty(&self) -> bool {
         self.clips.is_empty()
     }
@@ -36,33 +89,66 @@ impl SentryClip {
         let files = &self.files_per_camera(&camera);
         let result_file  = self.folder.join(format!("{}-{}.mp4", self.when.format("%Y-%m-%d_%H-%M-%S"), &camera.camera_file_name()));
         log::info!("Attaching files {:?} into file {}", files.iter().map(move |f| f.path.display().to_string()).collect::<Vec<String>>(), result_file.display());
+        let result_tmp_file_path = self.folder.join(
+            format!("{}-tmp.mp4", result_file.file_stem().and_then(|f| f.to_str()).ok_or(err_from_str(format!("Cannot get file name for file {}", result_file.display()).as_str()))?)
+        );
+
+        let metadata: Vec<ClipMetadata> = files.iter().map(|f| probe(f.path.as_path())).collect::<io::Result<Vec<ClipMetadata>>>()?;
+        if mp4mux::can_stitch_natively(&metadata) {
+            let scratch_file_path = self.folder.join(format!(
+                "{}-native-tmp.mp4",
+                result_file.file_stem().and_then(|f| f.to_str()).ok_or(err_from_str(format!("Cannot get file name for file {}", result_file.display()).as_str()))?
+            ));
+            let segments: Vec<&Path> = files.iter().map(|f| f.path.as_path()).collect();
+            match mp4mux::stitch(&segments, scratch_file_path.as_path()) {
+                Ok(()) => {
+                    std::fs::rename(&scratch_file_path, &result_tmp_file_path)?;
+                    return result_tmp_file_path.to_str().map(|s| s.to_string()).ok_or(err_from_str("Cannot build a path for temporary file"));
+                }
+                Err(err) => {
+                    let _ = remove_file(&scratch_file_path);
+                    log::warn!("Native MP4 stitching failed for {}, falling back to ffmpeg concat: {}", result_tmp_file_path.display(), err);
+                }
+            }
+        }
+
+        self.concatenate_camera_files_via_ffmpeg(files, camera, result_tmp_file_path.as_path())
+    }
+
+    /// Fallback used when segments aren't natively stitchable (or native
+    /// stitching failed): writes a concat-demuxer playlist and shells out to
+    /// ffmpeg. `-y` lets ffmpeg overwrite `result_tmp_file_path` if a failed
+    /// native attempt left a partial file there, and a non-zero exit status
+    /// is surfaced as an error instead of returning a corrupt/empty file.
+    fn concatenate_camera_files_via_ffmpeg(&self, files: &Vec<&CameraFile>, camera: &Camera, result_tmp_file_path: &Path) -> io::Result<String> {
         let date_format = "%Y%m%d_%H%M%S%3f";
         let now = Utc::now().format(date_format);
         let when = self.when.format(date_format);
         let playlist_filename = format!("/tmp/tesla_playlist_tmp_{}_{}_{}.txt", now, when ,camera.camera_file_name());
         create_temp_playlist(files, playlist_filename.as_str())?;
-        let result_tmp_file_path = self.folder.join(
-            format!("{}-tmp.mp4", result_file.file_stem().and_then(|f| f.to_str()).ok_or(err_from_str(format!("Cannot get file name for file {}", result_file.display()).as_str()))?)
-        );
-        let result_tmp_file= result_tmp_file_path.to_str().ok_or(err_from_str("Cannot build a path for temporary file"))?;
-        let _status = Command::new("ffmpeg")
-            .args(&["-f", "concat", "-safe", "0", "-i", playlist_filename.as_str(), "-c", "copy", result_tmp_file])
+        let result_tmp_file = result_tmp_file_path.to_str().ok_or(err_from_str("Cannot build a path for temporary file"))?;
+        let status = Command::new("ffmpeg")
+            .args(&["-y", "-f", "concat", "-safe", "0", "-i", playlist_filename.as_str(), "-c", "copy", result_tmp_file])
             .status()?;
+        if !status.success() {
+            return Err(err_from_str(format!("ffmpeg concat exited with {} for {}", status, result_tmp_file).as_str()));
+        }
         Ok(result_tmp_file.to_string())
     }
 
-    pub fn create_mosaic(&self, file_cameras: &Vec<(String, Camera)>) -> io::Result<()> {
+    /// Composes the mosaic with a single ffmpeg invocation, run through
+    /// `pool` so this doesn't spawn an unbounded ffmpeg child alongside
+    /// whatever else `pool` is currently running.
+    pub fn create_mosaic(&self, file_cameras: &Vec<(String, Camera)>, pool: &ProcessingPool) -> io::Result<()> {
 
         let mosaic_file = self.mosaic_file()?;
         log::info!("Composing mosaic clip '{}'", mosaic_file.display());
 
-        let filter_params = format!(
-            "nullsrc=size=1280x960 [base]; [0:v] setpts=PTS-STARTPTS, scale=640x480 [upperleft]; [1:v] setpts=PTS-STARTPTS, scale=640x480 [upperright]; \
-            [2:v] setpts=PTS-STARTPTS, scale=640x480 [lowerleft]; [3:v] setpts=PTS-STARTPTS, scale=640x480 [lowerright]; [base][upperleft] overlay=shortest=1 [tmp1]; \
-            [tmp1][upperright] overlay=shortest=1:x=640 [tmp2]; [tmp2][lowerleft] overlay=shortest=1:y=480 [tmp3]; [tmp3][lowerright] overlay=shortest=1:x=640:y=480, \
-            drawtext=text='%{{pts\\:gmtime\\:{}\\:%d-%m-%Y %T}}': x=100 : y=800 : box=0: fontsize=32: fontcolor=GoldenRod",
-            self.clips[0].start_time.timestamp()
-        );
+        let metadata: Vec<ClipMetadata> = file_cameras.iter()
+            .map(|f| probe(Path::new(f.0.as_str())))
+            .collect::<io::Result<Vec<ClipMetadata>>>()?;
+
+        let filter_params = build_mosaic_filter(&metadata, self.clips[0].start_time.timestamp());
         let mut args = vec![
             "-filter_complex",
             filter_params.as_str()
@@ -76,21 +162,158 @@ impl SentryClip {
         args.push("libx264");
         args.push(mosaic_file.to_str().ok_or(err_from_str("Cannot get path for mosaic file path"))?);
 
-        Command::new("ffmpeg")
-            .args(args)
-            .status()?;
+        pool.install(move || Command::new("ffmpeg").args(args).status())?;
 
         delete_files(file_cameras.iter().map(|t| t.0.clone()).collect())?;
 
         Ok(())
     }
 
+    /// Checks `cache` (a stat-only lookup, no files are opened) to see
+    /// whether this event's mosaic already exists and was built from these
+    /// exact source clips.
+    pub fn is_up_to_date(&self, cache: &ProcessingCache) -> io::Result<bool> {
+        if !self.mosaic_file()?.exists() {
+            return Ok(false);
+        }
+        let current = fingerprint_inputs(&self.clips.iter().map(|c| c.path.clone()).collect::<Vec<PathBuf>>())?;
+        Ok(cache.get(&self.folder).map_or(false, |cached| cached.inputs == current))
+    }
+
+    /// Records the current source clip fingerprints for this event so the
+    /// next run can recognize it as up to date.
+    pub fn record_processed(&self, cache: &mut ProcessingCache) -> io::Result<()> {
+        let inputs = fingerprint_inputs(&self.clips.iter().map(|c| c.path.clone()).collect::<Vec<PathBuf>>())?;
+        cache.set(&self.folder, CacheEntry { inputs });
+        Ok(())
+    }
+
+    /// Removes this event's produced mosaic and per-camera files. Intended
+    /// for the loser of a dedup group found by `crate::dedup`.
+    pub fn delete_outputs(&self) -> io::Result<()> {
+        let mut files = vec![self.mosaic_file()?.to_str().ok_or(err_from_str("Cannot get path for mosaic file path"))?.to_string()];
+        files.extend(self.distinct_cameras().iter().filter_map(|camera| {
+            self.folder.join(format!("{}-{}.mp4", self.when.format("%Y-%m-%d_%H-%M-%S"), camera.camera_file_name()))
+                .to_str().map(|s| s.to_string())
+        }));
+        delete_files(files)
+    }
+
     pub fn mosaic_file(&self) -> io::Result<PathBuf> {
         let mosaic_filename = format!("{}-mosaic.mp4", self.when.format("%Y-%m-%d_%H-%M-%S"));
         Ok(self.folder.parent().ok_or(err_from_str(format!("Cannot find parent folder of {}", self.folder.display()).as_str()))?
             .join(mosaic_filename.as_str()))
 
     }
+
+    /// Extracts a representative still from a produced mosaic or concatenated
+    /// clip, writing `<video_file stem>.jpg` next to it (e.g. the mosaic's
+    /// `<timestamp>-mosaic.mp4` becomes `<timestamp>-mosaic.jpg`). Seeks to
+    /// the clip's midpoint unless `seek_offset` overrides it, so callers can
+    /// build a contact-sheet gallery with consistent-looking thumbnails. Runs
+    /// through `pool` so this ffmpeg child is bounded alongside the rest of
+    /// the crate's concurrent ffmpeg work. `-y` lets a re-run overwrite an
+    /// existing thumbnail instead of ffmpeg blocking on an interactive
+    /// prompt, and a non-zero exit is surfaced as an error instead of
+    /// returning a path to a stale or missing file.
+    pub fn generate_thumbnail(&self, video_file: &Path, seek_offset: Option<f64>, width: u32, height: u32, pool: &ProcessingPool) -> io::Result<PathBuf> {
+        let thumbnail_file = thumbnail_file_for(video_file)?;
+        let offset = match seek_offset {
+            Some(offset) => offset,
+            None => probe(video_file)?.duration / 2.0,
+        };
+        log::info!("Generating thumbnail '{}' for '{}'", thumbnail_file.display(), video_file.display());
+
+        let status = pool.install(|| Command::new("ffmpeg")
+            .args(&[
+                "-y",
+                "-ss", offset.to_string().as_str(),
+                "-i", video_file.to_str().ok_or(err_from_str("Cannot build a path for thumbnail input"))?,
+                "-frames:v", "1",
+                "-vf", format!("scale={}:{}", width, height).as_str(),
+                thumbnail_file.to_str().ok_or(err_from_str("Cannot build a path for thumbnail file"))?,
+            ])
+            .status())?;
+        if !status.success() {
+            return Err(err_from_str(format!("ffmpeg thumbnail extraction exited with {} for {}", status, thumbnail_file.display()).as_str()));
+        }
+
+        Ok(thumbnail_file)
+    }
+}
+
+/// Default thumbnail size used when callers don't need a custom one.
+pub const DEFAULT_THUMBNAIL_WIDTH: u32 = 320;
+pub const DEFAULT_THUMBNAIL_HEIGHT: u32 = 240;
+
+fn thumbnail_file_for(video_file: &Path) -> io::Result<PathBuf> {
+    let stem = video_file.file_stem().and_then(|f| f.to_str())
+        .ok_or(err_from_str(format!("Cannot get file name for file {}", video_file.display()).as_str()))?;
+    let parent = video_file.parent().ok_or(err_from_str(format!("Cannot find parent folder of {}", video_file.display()).as_str()))?;
+    Ok(parent.join(format!("{}.jpg", stem)))
+}
+
+/// Largest tile cell we scale each camera feed down to; cameras probing
+/// smaller than this keep their native size, so the cell is a cap rather
+/// than a forced resolution. The overall mosaic canvas grows with the grid
+/// instead of assuming a fixed four-quadrant size.
+const TILE_WIDTH: u32 = 640;
+const TILE_HEIGHT: u32 = 480;
+
+/// Picks a close-to-square columns/rows layout for `count` camera tiles.
+fn grid_dimensions(count: usize) -> (usize, usize) {
+    let columns = (count as f64).sqrt().ceil().max(1.0) as usize;
+    let rows = (count + columns - 1) / columns;
+    (columns, rows)
+}
+
+/// Scales `meta`'s own resolution down to fit within a `TILE_WIDTH` x
+/// `TILE_HEIGHT` cell, preserving its aspect ratio instead of stretching it
+/// to a fixed tile size. Falls back to the full cell when ffprobe didn't
+/// report a usable resolution. libx264 needs even dimensions.
+fn tile_size(meta: &ClipMetadata) -> (u32, u32) {
+    if meta.width == 0 || meta.height == 0 {
+        return (TILE_WIDTH, TILE_HEIGHT);
+    }
+    let scale = f64::min(1.0, f64::min(TILE_WIDTH as f64 / meta.width as f64, TILE_HEIGHT as f64 / meta.height as f64));
+    let width = ((meta.width as f64 * scale).round() as u32).max(2);
+    let height = ((meta.height as f64 * scale).round() as u32).max(2);
+    (width & !1, height & !1)
+}
+
+/// Builds the `filter_complex` graph for the mosaic: a `nullsrc` canvas sized
+/// to the grid, one aspect-preserving `scale` per input tile (sized from its
+/// own probed resolution), and an `overlay` chain centering each tile within
+/// its computed grid cell.
+fn build_mosaic_filter(metadata: &[ClipMetadata], timestamp: i64) -> String {
+    let (columns, rows) = grid_dimensions(metadata.len());
+    let base_width = columns as u32 * TILE_WIDTH;
+    let base_height = rows as u32 * TILE_HEIGHT;
+
+    let mut filter = format!("nullsrc=size={}x{} [base]; ", base_width, base_height);
+    let sizes: Vec<(u32, u32)> = metadata.iter().map(tile_size).collect();
+    for (i, (width, height)) in sizes.iter().enumerate() {
+        filter.push_str(&format!("[{}:v] setpts=PTS-STARTPTS, scale={}x{} [tile{}]; ", i, width, height, i));
+    }
+
+    let mut last_label = "base".to_string();
+    let last_index = metadata.len().saturating_sub(1);
+    for (i, (width, height)) in sizes.iter().enumerate() {
+        let x = (i % columns) as u32 * TILE_WIDTH + (TILE_WIDTH.saturating_sub(*width)) / 2;
+        let y = (i / columns) as u32 * TILE_HEIGHT + (TILE_HEIGHT.saturating_sub(*height)) / 2;
+        filter.push_str(&format!("[{}][tile{}] overlay=shortest=1:x={}:y={}", last_label, i, x, y));
+        if i == last_index {
+            filter.push_str(&format!(
+                ", drawtext=text='%{{pts\\:gmtime\\:{}\\:%d-%m-%Y %T}}': x=100 : y={}: box=0: fontsize=32: fontcolor=GoldenRod",
+                timestamp, base_height.saturating_sub(160)
+            ));
+        } else {
+            let next_label = format!("tmp{}", i);
+            filter.push_str(&format!(" [{}]; ", next_label));
+            last_label = next_label;
+        }
+    }
+    filter
 }
 
 fn delete_files(files: Vec<String>) -> io::Result<()> {
@@ -112,7 +335,7 @@ fn create_temp_playlist(files: &Vec<&CameraFile>, playlist_filename: &str) -> io
 }
 
 fn process_folder(root: &DirEntry) -> io::Result<Vec<CameraFile>> {
-    let mut clips: Vec<CameraFile> = list_files(root)?.into_iter().filter_map(|e| {
+    let mut clips: Vec<CameraFile> = list_files(root)?.into_par_iter().filter_map(|e| {
         match CameraFile::from(e.path().as_path()) {
             Ok(f) => { Some(f) },
             Err(err) => { log::error!("Found error processing clip {}: {}", &e.path().display(), err); None },
@@ -139,4 +362,49 @@ fn list_files(root: &DirEntry) -> io::Result<Vec<DirEntry>> {
     }).collect();
     log::info!("Found {} clip files in folder {}", &children.len(), &root.path().display());
     Ok(children)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_dimensions_picks_close_to_square_layout() {
+        assert_eq!(grid_dimensions(1), (1, 1));
+        assert_eq!(grid_dimensions(4), (2, 2));
+        assert_eq!(grid_dimensions(5), (3, 2));
+        assert_eq!(grid_dimensions(6), (3, 2));
+    }
+
+    fn meta(width: u32, height: u32) -> ClipMetadata {
+        ClipMetadata { width, height, duration: 0.0, codec: "h264".to_string(), time_base: "1/30000".to_string() }
+    }
+
+    #[test]
+    fn tile_size_preserves_aspect_ratio_within_the_cell() {
+        assert_eq!(tile_size(&meta(1280, 960)), (TILE_WIDTH, TILE_HEIGHT));
+        // 16:9 source capped by width, should shrink below TILE_HEIGHT.
+        let (width, height) = tile_size(&meta(1920, 1080));
+        assert_eq!(width, TILE_WIDTH);
+        assert!(height < TILE_HEIGHT);
+    }
+
+    #[test]
+    fn tile_size_falls_back_to_full_cell_without_probed_resolution() {
+        assert_eq!(tile_size(&meta(0, 0)), (TILE_WIDTH, TILE_HEIGHT));
+    }
+
+    #[test]
+    fn tile_size_keeps_native_size_for_cameras_smaller_than_the_cell() {
+        assert_eq!(tile_size(&meta(320, 240)), (320, 240));
+    }
+
+    #[test]
+    fn build_mosaic_filter_scales_each_tile_from_its_own_resolution() {
+        let metadata = vec![meta(1280, 960), meta(1920, 1080)];
+        let filter = build_mosaic_filter(&metadata, 0);
+        assert!(filter.contains(&format!("scale={}x{}", TILE_WIDTH, TILE_HEIGHT)));
+        let (width, height) = tile_size(&metadata[1]);
+        assert!(filter.contains(&format!("scale={}x{}", width, height)));
+    }
+}
@@ -0,0 +1,348 @@
+extern crate image;
+
+use self::image::{GrayImage, imageops::FilterType};
+use crate::ffmpeg_pool::ProcessingPool;
+use crate::formats::err_from_str;
+use crate::metadata::probe;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// Side of the grid each sampled frame is downscaled to before the DCT runs.
+const HASH_SAMPLE_SIZE: u32 = 32;
+/// Side of the low-frequency corner kept from the DCT; `HASH_BITS * HASH_BITS`
+/// bits per sampled frame.
+const HASH_BITS: u32 = 8;
+
+/// A perceptual hash over a fixed number of evenly-spaced frames of a clip.
+///
+/// Built by concatenating one DCT-based frame hash per sample, so two
+/// `VideoHash`es are only comparable (via `hamming_distance`) when they were
+/// built with the same frame count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoHash {
+    bits: Vec<u8>,
+}
+
+impl VideoHash {
+    /// Popcount of the XOR of the two byte vectors. Returns `None` rather
+    /// than silently truncating to the shorter length when `self` and
+    /// `other` weren't built with the same sample count (e.g. one came from
+    /// a clip too short to yield all of `frame_count`'s frames) — such a
+    /// pair isn't a meaningful Hamming distance.
+    pub fn hamming_distance(&self, other: &VideoHash) -> Option<u32> {
+        if self.bits.len() != other.bits.len() {
+            return None;
+        }
+        Some(self.bits.iter().zip(other.bits.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum())
+    }
+}
+
+/// Extracts `frame_count` evenly-spaced frames from `video_file`, hashes each
+/// with a DCT-based perceptual hash, and concatenates them into one
+/// `VideoHash`. Clips shorter than `frame_count` frames fall back to hashing
+/// whatever frames could actually be extracted instead of erroring. Each
+/// frame extraction spawns an ffmpeg child, so it's run through `pool` to
+/// keep it bounded alongside the rest of the crate's concurrent ffmpeg work.
+pub fn hash_clip(video_file: &Path, frame_count: usize, pool: &ProcessingPool) -> io::Result<VideoHash> {
+    let duration = probe(video_file)?.duration;
+    let mut bits: Vec<u8> = Vec::new();
+
+    for i in 0..frame_count {
+        let offset = if frame_count <= 1 { 0.0 } else { duration * i as f64 / frame_count as f64 };
+        match pool.install(|| extract_frame(video_file, offset)) {
+            Ok(frame) => bits.extend_from_slice(&hash_frame(&frame)),
+            Err(err) => log::warn!("Skipping frame {} of {} while hashing {}: {}", i, frame_count, video_file.display(), err),
+        }
+    }
+
+    Ok(VideoHash { bits })
+}
+
+fn extract_frame(video_file: &Path, offset: f64) -> io::Result<GrayImage> {
+    let frame_path = std::env::temp_dir().join(format!(
+        "tesla_hash_frame_{}_{:.3}.png",
+        video_file.file_stem().and_then(|f| f.to_str()).unwrap_or("clip"),
+        offset
+    ));
+    let frame_file = frame_path.to_str().ok_or(err_from_str("Cannot build a path for hash frame"))?;
+
+    Command::new("ffmpeg")
+        .args(&[
+            "-y",
+            "-ss", offset.to_string().as_str(),
+            "-i", video_file.to_str().ok_or(err_from_str("Cannot build a path for hash input"))?,
+            "-frames:v", "1",
+            "-vf", format!("scale={}:{}", HASH_SAMPLE_SIZE, HASH_SAMPLE_SIZE).as_str(),
+            frame_file,
+        ])
+        .status()?;
+
+    let image = image::open(&frame_path)
+        .map_err(|e| err_from_str(format!("Cannot decode hash frame {}: {}", frame_path.display(), e).as_str()))?
+        .resize_exact(HASH_SAMPLE_SIZE, HASH_SAMPLE_SIZE, FilterType::Lanczos3)
+        .to_luma8();
+    let _ = std::fs::remove_file(&frame_path);
+    Ok(image)
+}
+
+/// DCT-II over the sampled frame, keeping the `HASH_BITS`x`HASH_BITS`
+/// low-frequency corner (skipping the DC term) and thresholding against the
+/// median to produce one bit per coefficient.
+fn hash_frame(frame: &GrayImage) -> Vec<u8> {
+    let n = HASH_SAMPLE_SIZE as usize;
+    let pixels: Vec<f64> = frame.pixels().map(|p| p.0[0] as f64).collect();
+
+    let mut coefficients = vec![0f64; n * n];
+    for u in 0..n {
+        for v in 0..n {
+            let mut sum = 0f64;
+            for x in 0..n {
+                for y in 0..n {
+                    sum += pixels[x * n + y]
+                        * ((std::f64::consts::PI * (2.0 * x as f64 + 1.0) * u as f64) / (2.0 * n as f64)).cos()
+                        * ((std::f64::consts::PI * (2.0 * y as f64 + 1.0) * v as f64) / (2.0 * n as f64)).cos();
+                }
+            }
+            let cu = if u == 0 { 1.0 / (2f64).sqrt() } else { 1.0 };
+            let cv = if v == 0 { 1.0 / (2f64).sqrt() } else { 1.0 };
+            coefficients[u * n + v] = 0.25 * cu * cv * sum;
+        }
+    }
+
+    let bits_side = HASH_BITS as usize;
+    let mut low_freq: Vec<f64> = Vec::with_capacity(bits_side * bits_side - 1);
+    for u in 0..bits_side {
+        for v in 0..bits_side {
+            if u == 0 && v == 0 { continue; }
+            low_freq.push(coefficients[u * n + v]);
+        }
+    }
+    let mut sorted = low_freq.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = sorted[sorted.len() / 2];
+
+    let mut bytes = vec![0u8; (low_freq.len() + 7) / 8];
+    for (i, value) in low_freq.iter().enumerate() {
+        if *value > median {
+            bytes[i / 8] |= 1 << (i % 8);
+        }
+    }
+    bytes
+}
+
+/// A BK-tree keyed by Hamming distance, used to cluster event indices whose
+/// `VideoHash`es fall within a caller-supplied tolerance.
+pub struct BkTree {
+    root: Option<Box<BkNode>>,
+}
+
+struct BkNode {
+    event_index: usize,
+    hash: VideoHash,
+    children: HashMap<u32, Box<BkNode>>,
+}
+
+impl BkTree {
+    pub fn new() -> BkTree {
+        BkTree { root: None }
+    }
+
+    pub fn insert(&mut self, event_index: usize, hash: VideoHash) {
+        match &mut self.root {
+            None => { self.root = Some(Box::new(BkNode { event_index, hash, children: HashMap::new() })); }
+            Some(root) => root.insert(event_index, hash),
+        }
+    }
+
+    /// Returns the event indices within `tolerance` Hamming distance of `hash`.
+    pub fn query(&self, hash: &VideoHash, tolerance: u32) -> Vec<usize> {
+        let mut matches = Vec::new();
+        if let Some(root) = &self.root {
+            root.query(hash, tolerance, &mut matches);
+        }
+        matches
+    }
+}
+
+/// Bucket key used when two hashes can't be compared (different sample
+/// counts). Real Hamming distances are bounded by the hash's bit length, far
+/// below this, so it can't collide with a genuine bucket.
+const INCOMPARABLE_BUCKET: u32 = u32::MAX;
+
+impl BkNode {
+    fn insert(&mut self, event_index: usize, hash: VideoHash) {
+        let distance = match self.hash.hamming_distance(&hash) {
+            Some(distance) => distance,
+            None => {
+                log::warn!("Cannot compare video hashes of different lengths while inserting into BK-tree; bucketing as incomparable");
+                INCOMPARABLE_BUCKET
+            }
+        };
+        match self.children.get_mut(&distance) {
+            Some(child) => child.insert(event_index, hash),
+            None => { self.children.insert(distance, Box::new(BkNode { event_index, hash, children: HashMap::new() })); }
+        }
+    }
+
+    fn query(&self, hash: &VideoHash, tolerance: u32, matches: &mut Vec<usize>) {
+        let distance = match self.hash.hamming_distance(hash) {
+            Some(distance) => distance,
+            None => {
+                log::warn!("Cannot compare video hashes of different lengths while querying BK-tree; visiting all children without pruning");
+                for child in self.children.values() {
+                    child.query(hash, tolerance, matches);
+                }
+                return;
+            }
+        };
+        if distance <= tolerance {
+            matches.push(self.event_index);
+        }
+        let lower = distance.saturating_sub(tolerance);
+        let upper = distance + tolerance;
+        for (child_distance, child) in &self.children {
+            if *child_distance >= lower && *child_distance <= upper {
+                child.query(hash, tolerance, matches);
+            }
+        }
+    }
+}
+
+/// Merges `groups[source]` into `groups[target]`, repointing every moved
+/// member's `group_of` entry. No-op when `source` and `target` are already
+/// the same group.
+fn merge_groups(groups: &mut Vec<Vec<usize>>, group_of: &mut Vec<Option<usize>>, target: usize, source: usize) {
+    if target == source {
+        return;
+    }
+    let members = std::mem::take(&mut groups[source]);
+    for member in &members {
+        group_of[*member] = Some(target);
+    }
+    groups[target].extend(members);
+}
+
+/// Adds `index` to `groups[target]`, merging its current group into `target`
+/// first if it already belongs to one.
+fn assign_to_group(groups: &mut Vec<Vec<usize>>, group_of: &mut Vec<Option<usize>>, target: usize, index: usize) {
+    match group_of[index] {
+        Some(existing) if existing == target => {}
+        Some(existing) => merge_groups(groups, group_of, target, existing),
+        None => {
+            group_of[index] = Some(target);
+            groups[target].push(index);
+        }
+    }
+}
+
+/// Hashes each event's representative clip and clusters event indices whose
+/// hashes fall within `tolerance` Hamming distance of one another.
+///
+/// Clustering is transitive (single-linkage): an event within tolerance of
+/// *any* member already in a group joins that group, even if it isn't within
+/// tolerance of the member that founded it. Without this, a slow drift across
+/// many near-identical events (e.g. a parked car recorded repeatedly over a
+/// day) would fracture into several groups instead of one. `pool` bounds the
+/// ffmpeg children spawned while hashing each clip.
+pub fn find_duplicate_groups(video_files: &[&Path], frame_count: usize, tolerance: u32, pool: &ProcessingPool) -> io::Result<Vec<Vec<usize>>> {
+    let hashes: Vec<VideoHash> = video_files.iter()
+        .map(|f| hash_clip(f, frame_count, pool))
+        .collect::<io::Result<Vec<VideoHash>>>()?;
+
+    let mut tree = BkTree::new();
+    let mut group_of: Vec<Option<usize>> = vec![None; hashes.len()];
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+
+    for (index, hash) in hashes.iter().enumerate() {
+        let matches = tree.query(hash, tolerance);
+        if !matches.is_empty() {
+            let group_index = matches.iter().filter_map(|m| group_of[*m]).next()
+                .unwrap_or_else(|| {
+                    let new_index = groups.len();
+                    groups.push(Vec::new());
+                    new_index
+                });
+
+            for m in &matches {
+                assign_to_group(&mut groups, &mut group_of, group_index, *m);
+            }
+            assign_to_group(&mut groups, &mut group_of, group_index, index);
+        }
+        tree.insert(index, hash.clone());
+    }
+
+    Ok(groups.into_iter().filter(|g| !g.is_empty()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(bits: &[u8]) -> VideoHash {
+        VideoHash { bits: bits.to_vec() }
+    }
+
+    #[test]
+    fn hamming_distance_counts_differing_bits() {
+        assert_eq!(hash(&[0b1111_0000]).hamming_distance(&hash(&[0b1010_0000])), Some(2));
+        assert_eq!(hash(&[0, 0]).hamming_distance(&hash(&[0, 0])), Some(0));
+    }
+
+    #[test]
+    fn hamming_distance_rejects_mismatched_lengths() {
+        assert_eq!(hash(&[0, 0]).hamming_distance(&hash(&[0])), None);
+    }
+
+    #[test]
+    fn bk_tree_query_finds_hashes_within_tolerance() {
+        let mut tree = BkTree::new();
+        tree.insert(0, hash(&[0b0000_0000]));
+        tree.insert(1, hash(&[0b0000_0001]));
+        tree.insert(2, hash(&[0b1111_1111]));
+
+        let mut matches = tree.query(&hash(&[0b0000_0000]), 1);
+        matches.sort();
+        assert_eq!(matches, vec![0, 1]);
+    }
+
+    #[test]
+    fn bk_tree_query_ignores_incomparable_hashes_instead_of_matching_them() {
+        let mut tree = BkTree::new();
+        tree.insert(0, hash(&[0b0000_0000]));
+        tree.insert(1, hash(&[0b0000_0000, 0b0000_0000]));
+
+        // A mismatched-length hash can't be compared, so it shouldn't
+        // silently come back as a match for a high tolerance query.
+        let matches = tree.query(&hash(&[0b0000_0000]), 64);
+        assert_eq!(matches, vec![0]);
+    }
+
+    #[test]
+    fn merge_groups_moves_members_and_repoints_their_group() {
+        let mut groups = vec![vec![0, 1], vec![2]];
+        let mut group_of = vec![Some(0), Some(0), Some(1)];
+
+        merge_groups(&mut groups, &mut group_of, 0, 1);
+
+        assert_eq!(groups, vec![vec![0, 1, 2], vec![]]);
+        assert_eq!(group_of, vec![Some(0), Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn assign_to_group_adds_an_ungrouped_index_instead_of_dropping_it() {
+        // Simulates the drift scenario: index 2 is within tolerance of index 1
+        // (already in group 0 alongside index 0) but not of index 0 itself.
+        // It must be folded into the existing group rather than ignored.
+        let mut groups = vec![vec![0, 1]];
+        let mut group_of = vec![Some(0), Some(0), None];
+
+        assign_to_group(&mut groups, &mut group_of, 0, 2);
+
+        assert_eq!(groups, vec![vec![0, 1, 2]]);
+        assert_eq!(group_of, vec![Some(0), Some(0), Some(0)]);
+    }
+}
@@ -0,0 +1,34 @@
+extern crate rayon;
+
+use self::rayon::{ThreadPool, ThreadPoolBuilder};
+use crate::formats::err_from_str;
+use std::io;
+
+/// Caps how many ffmpeg children (and `SentryClip` builds) may run at once.
+///
+/// Every concurrent traversal in this crate (events across a tree, cameras
+/// within one event) is funneled through a single `ProcessingPool` so that a
+/// large SentryClips library doesn't fork hundreds of ffmpeg processes at
+/// the same time. Every ffmpeg-spawning call site — camera concatenation,
+/// mosaic composition, thumbnail extraction and dedup frame hashing — takes
+/// the same `&ProcessingPool` and runs its child through `install` so none
+/// of them can bypass the cap.
+pub struct ProcessingPool {
+    pool: ThreadPool,
+}
+
+impl ProcessingPool {
+    pub fn new(parallelism: usize) -> io::Result<ProcessingPool> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(parallelism)
+            .build()
+            .map_err(|e| err_from_str(format!("Cannot build processing pool: {}", e).as_str()))?;
+        Ok(ProcessingPool { pool })
+    }
+
+    pub fn install<OP, R>(&self, op: OP) -> R
+        where OP: FnOnce() -> R + Send, R: Send
+    {
+        self.pool.install(op)
+    }
+}
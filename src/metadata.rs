@@ -0,0 +1,45 @@
+extern crate serde_json;
+
+use self::serde_json::Value;
+use crate::formats::err_from_str;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// Stream-level facts about a single camera clip, probed with ffprobe.
+///
+/// `create_mosaic` uses `width`/`height` to size and place each tile instead
+/// of assuming every camera is 640x480. `codec`/`time_base` let
+/// `mp4mux::can_stitch_natively` check whether segments share close enough
+/// stream parameters to be appended sample-for-sample.
+#[derive(Debug, Clone)]
+pub struct ClipMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub duration: f64,
+    pub codec: String,
+    pub time_base: String,
+}
+
+/// Shells out to `ffprobe -show_streams -of json` and extracts the first
+/// video stream's resolution, duration and codec.
+pub fn probe(path: &Path) -> io::Result<ClipMetadata> {
+    let output = Command::new("ffprobe")
+        .args(&["-v", "error", "-show_streams", "-of", "json", path.to_str().ok_or(err_from_str("Cannot build a path for ffprobe input"))?])
+        .output()?;
+
+    let parsed: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| err_from_str(format!("Cannot parse ffprobe output for {}: {}", path.display(), e).as_str()))?;
+
+    let stream = parsed["streams"].as_array()
+        .and_then(|streams| streams.iter().find(|s| s["codec_type"] == "video"))
+        .ok_or(err_from_str(format!("No video stream found for {}", path.display()).as_str()))?;
+
+    let width = stream["width"].as_u64().ok_or(err_from_str(format!("No width in ffprobe output for {}", path.display()).as_str()))? as u32;
+    let height = stream["height"].as_u64().ok_or(err_from_str(format!("No height in ffprobe output for {}", path.display()).as_str()))? as u32;
+    let codec = stream["codec_name"].as_str().unwrap_or("unknown").to_string();
+    let duration = stream["duration"].as_str().and_then(|d| d.parse::<f64>().ok()).unwrap_or(0.0);
+    let time_base = stream["time_base"].as_str().unwrap_or("").to_string();
+
+    Ok(ClipMetadata { width, height, duration, codec, time_base })
+}
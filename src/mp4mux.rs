@@ -0,0 +1,117 @@
+extern crate mp4;
+
+use self::mp4::{Mp4Reader, Mp4Writer, Mp4Config, TrackConfig, MediaConfig};
+use crate::formats::err_from_str;
+use crate::metadata::ClipMetadata;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::io;
+use std::path::Path;
+
+/// Checks that every segment's probed codec, resolution and time base match,
+/// i.e. that `stitch` can treat their samples as directly appendable into
+/// one track. Segments sharing a codec but differing in resolution or
+/// timescale are NOT stitchable natively and should fall back to the ffmpeg
+/// concat-demuxer path instead.
+pub fn can_stitch_natively(metadata: &[ClipMetadata]) -> bool {
+    let first = match metadata.first() {
+        Some(first) => first,
+        None => return true,
+    };
+    metadata.iter().all(|m| {
+        m.codec == first.codec && m.width == first.width && m.height == first.height && m.time_base == first.time_base
+    })
+}
+
+/// Stitches same-codec MP4 segments into one continuous file by appending
+/// samples into a single track with corrected timestamps, instead of
+/// shelling out to `ffmpeg -f concat -c copy`. Avoids the `/tmp` playlist
+/// file and surfaces demux/mux errors directly instead of an opaque ffmpeg
+/// exit status.
+///
+/// Only handles the stream-copy case: every segment must share the same
+/// codec and track parameters, which `can_stitch_natively` should be used to
+/// verify beforehand. Callers should fall back to the ffmpeg concat-demuxer
+/// path when that isn't true.
+pub fn stitch(segments: &[&Path], output: &Path) -> io::Result<()> {
+    let mut readers: Vec<Mp4Reader<BufReader<File>>> = Vec::with_capacity(segments.len());
+    for segment in segments {
+        let file = File::open(segment)?;
+        let size = file.metadata()?.len();
+        let reader = Mp4Reader::read_header(BufReader::new(file), size)
+            .map_err(|e| err_from_str(format!("Cannot read MP4 header for {}: {}", segment.display(), e).as_str()))?;
+        readers.push(reader);
+    }
+
+    let first = readers.first().ok_or(err_from_str("No segments to stitch"))?;
+    let first_track = first.tracks().values().next()
+        .ok_or(err_from_str("First segment has no tracks"))?;
+
+    let config = Mp4Config {
+        major_brand: first.major_brand().clone(),
+        minor_version: first.minor_version(),
+        compatible_brands: first.compatible_brands().to_vec(),
+        timescale: first_track.timescale(),
+    };
+
+    let out_file = File::create(output)?;
+    let mut writer = Mp4Writer::write_start(BufWriter::new(out_file), &config)
+        .map_err(|e| err_from_str(format!("Cannot start MP4 writer for {}: {}", output.display(), e).as_str()))?;
+
+    let media_config = track_media_config(first_track)?;
+    writer.add_track(&TrackConfig { track_type: first_track.track_type().map_err(|e| err_from_str(e.to_string().as_str()))?, timescale: first_track.timescale(), language: first_track.language().to_string(), media_conf: media_config })
+        .map_err(|e| err_from_str(format!("Cannot add output track: {}", e).as_str()))?;
+
+    let mut time_offset: i64 = 0;
+    for reader in &mut readers {
+        let track_id = reader.tracks().keys().next().cloned()
+            .ok_or(err_from_str("Segment has no tracks"))?;
+        let sample_count = reader.sample_count(track_id)
+            .map_err(|e| err_from_str(format!("Cannot count samples: {}", e).as_str()))?;
+        let mut last_end: i64 = 0;
+
+        for sample_id in 1..=sample_count {
+            if let Some(mut sample) = reader.read_sample(track_id, sample_id)
+                .map_err(|e| err_from_str(format!("Cannot read sample {}: {}", sample_id, e).as_str()))? {
+                last_end = sample.start_time as i64 + sample.duration as i64;
+                sample.start_time = (sample.start_time as i64 + time_offset) as u64;
+                writer.write_sample(1, &sample)
+                    .map_err(|e| err_from_str(format!("Cannot write sample {}: {}", sample_id, e).as_str()))?;
+            }
+        }
+        time_offset += last_end;
+    }
+
+    writer.write_end().map_err(|e| err_from_str(format!("Cannot finalize MP4 {}: {}", output.display(), e).as_str()))
+}
+
+fn track_media_config(track: &mp4::Mp4Track) -> io::Result<MediaConfig> {
+    track.media_config().map_err(|e| err_from_str(format!("Unsupported track codec for native stitching: {}", e).as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(width: u32, height: u32, codec: &str, time_base: &str) -> ClipMetadata {
+        ClipMetadata { width, height, duration: 0.0, codec: codec.to_string(), time_base: time_base.to_string() }
+    }
+
+    #[test]
+    fn can_stitch_natively_accepts_matching_segments() {
+        let metadata = vec![meta(1280, 960, "h264", "1/30000"), meta(1280, 960, "h264", "1/30000")];
+        assert!(can_stitch_natively(&metadata));
+    }
+
+    #[test]
+    fn can_stitch_natively_rejects_same_codec_different_resolution() {
+        let metadata = vec![meta(1280, 960, "h264", "1/30000"), meta(1920, 1080, "h264", "1/30000")];
+        assert!(!can_stitch_natively(&metadata));
+    }
+
+    #[test]
+    fn can_stitch_natively_rejects_different_time_base() {
+        let metadata = vec![meta(1280, 960, "h264", "1/30000"), meta(1280, 960, "h264", "1/60000")];
+        assert!(!can_stitch_natively(&metadata));
+    }
+}